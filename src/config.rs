@@ -1,48 +1,697 @@
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
 
 use ini::Ini;
+use regex::Regex;
 
-/// A parsed task entry from the INI file.
+/// Section holding global settings rather than a task.
+const SETTINGS_SECTION: &str = "tequio";
+
+/// Table holding the tasks in TOML and YAML configs.
+const TASKS_TABLE: &str = "tasks";
+
+/// Default delay between a task exiting and being restarted.
+const DEFAULT_BACKOFF_MS: u64 = 1000;
+
+/// Default time a task gets to exit after SIGTERM before it is killed.
+const DEFAULT_KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When a task should be re-spawned after its process exits.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    No,
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "no" => Some(Self::No),
+            "on-failure" => Some(Self::OnFailure),
+            "always" => Some(Self::Always),
+            _ => None,
+        }
+    }
+
+    /// Whether a process that exited with the given outcome should be restarted.
+    pub fn should_restart(self, success: bool) -> bool {
+        match self {
+            Self::No => false,
+            Self::OnFailure => !success,
+            Self::Always => true,
+        }
+    }
+}
+
+/// Errors produced while loading or validating the task config.
+#[derive(Debug)]
+pub enum ConfigError {
+    ReadFailed { path: String, reason: String },
+    MissingCommand { task: String },
+    InvalidValue { task: String, key: String, value: String },
+    InvalidRegex { task: String, pattern: String, reason: String },
+    ConflictingKeys { task: String, first: String, second: String },
+    UndefinedVariable { task: String, var: String },
+    UnknownDependency { task: String, dep: String },
+    Cycle { nodes: Vec<String> },
+    UnknownTask { task: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReadFailed { path, reason } => {
+                write!(f, "failed to read config file '{path}': {reason}")
+            }
+            Self::MissingCommand { task } => write!(f, "task '{task}' has no command"),
+            Self::InvalidValue { task, key, value } => {
+                write!(f, "task '{task}' has invalid {key} '{value}'")
+            }
+            Self::InvalidRegex { task, pattern, reason } => {
+                write!(f, "task '{task}' has invalid ready_check_regex '{pattern}': {reason}")
+            }
+            Self::ConflictingKeys { task, first, second } => {
+                write!(f, "task '{task}' cannot set both '{first}' and '{second}'")
+            }
+            Self::UndefinedVariable { task, var } => {
+                write!(f, "task '{task}' references undefined variable '{var}'")
+            }
+            Self::UnknownDependency { task, dep } => {
+                write!(f, "task '{task}' depends on unknown task '{dep}'")
+            }
+            Self::Cycle { nodes } => {
+                write!(f, "dependency cycle detected among tasks: {}", nodes.join(", "))
+            }
+            Self::UnknownTask { task } => write!(f, "task '{task}' not found in config"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// How a task's stdout is inspected to decide that it is ready.
+#[derive(Clone)]
+pub enum ReadyCheck {
+    /// The line contains this substring.
+    Literal(String),
+    /// The line matches this regular expression.
+    Regex(Regex),
+}
+
+impl ReadyCheck {
+    pub fn matches(&self, line: &str) -> bool {
+        let line = line.trim();
+        match self {
+            Self::Literal(check) => line.contains(check.as_str()),
+            Self::Regex(re) => re.is_match(line),
+        }
+    }
+}
+
+/// How a task's command is launched.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Shell {
+    /// Run the command through this shell with `-c` (or `/C` for `cmd`).
+    Program(String),
+    /// Split the command into words and exec it directly.
+    None,
+}
+
+impl Shell {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "" => None,
+            "none" | "false" => Some(Self::None),
+            "true" => Some(Self::default()),
+            program => Some(Self::Program(program.to_string())),
+        }
+    }
+}
+
+impl Default for Shell {
+    /// `sh` on Unix, `cmd` on Windows.
+    fn default() -> Self {
+        Self::Program(if cfg!(windows) { "cmd" } else { "sh" }.to_string())
+    }
+}
+
+/// A parsed task entry from the config file.
+#[derive(Clone)]
 pub struct TaskEntry {
     pub name: String,
     pub command: String,
+    /// Overrides the global `shell` setting when set.
+    pub shell: Option<Shell>,
     pub work_dir: Option<String>,
+    pub env: Vec<(String, String)>,
+    pub log_file: Option<String>,
     pub depends_on: Vec<String>,
-    pub ready_check: Option<String>,
+    pub depends_on_success: Vec<String>,
+    pub ready_check: Option<ReadyCheck>,
+    pub ready_port: Option<u16>,
+    pub restart: RestartPolicy,
+    pub max_restarts: Option<u32>,
+    pub backoff_ms: u64,
+    pub kill_timeout: Duration,
+    pub ready_timeout: Option<Duration>,
+    pub timeout: Option<Duration>,
+    /// Glob patterns, relative to the working directory, that restart the task
+    /// when a matching file changes.
+    pub watch: Vec<String>,
+}
+
+impl TaskEntry {
+    /// A task running `command` with every other key left at its default.
+    #[cfg(test)]
+    pub fn for_test(name: &str, command: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            command: command.to_string(),
+            shell: None,
+            work_dir: None,
+            env: Vec::new(),
+            log_file: None,
+            depends_on: Vec::new(),
+            depends_on_success: Vec::new(),
+            ready_check: None,
+            ready_port: None,
+            restart: RestartPolicy::No,
+            max_restarts: None,
+            backoff_ms: DEFAULT_BACKOFF_MS,
+            kill_timeout: DEFAULT_KILL_TIMEOUT,
+            ready_timeout: None,
+            timeout: None,
+            watch: Vec::new(),
+        }
+    }
+
+    /// Whether to restart the task after its process exited with the given
+    /// outcome, counting the restarts already made in `attempt`.
+    pub fn should_restart(&self, success: bool, attempt: u32) -> bool {
+        self.restart.should_restart(success) && self.max_restarts.is_none_or(|max| attempt < max)
+    }
+
+    /// Every upstream task, whether waited on for readiness or for success.
+    pub fn dependencies(&self) -> impl Iterator<Item = &String> {
+        self.depends_on.iter().chain(&self.depends_on_success)
+    }
+
+    /// The program and arguments that run this task's command.
+    ///
+    /// With a shell this is `<shell> -c <command>` (`/C` for `cmd`). With
+    /// `shell = none` the command is split into words the way a POSIX shell
+    /// would, but without expansion, pipes, or redirection.
+    pub fn argv(&self) -> Result<Vec<String>, ConfigError> {
+        match self.shell.clone().unwrap_or_default() {
+            Shell::Program(program) => {
+                let flag = if is_cmd(&program) { "/C" } else { "-c" };
+                Ok(vec![program, flag.to_string(), self.command.clone()])
+            }
+            Shell::None => match shell_words::split(&self.command) {
+                Ok(argv) if !argv.is_empty() => Ok(argv),
+                _ => Err(invalid(&self.name, "command", &self.command)),
+            },
+        }
+    }
+}
+
+/// Whether `program` is Windows' `cmd`, which takes `/C` rather than `-c`.
+pub fn is_cmd(program: &str) -> bool {
+    Path::new(program)
+        .file_stem()
+        .is_some_and(|stem| stem.eq_ignore_ascii_case("cmd"))
+}
+
+/// Global settings from the `[tequio]` section of the config file.
+#[derive(Default)]
+pub struct Settings {
+    pub max_parallel: Option<NonZeroUsize>,
+    pub shell: Option<Shell>,
+    pub prefix: bool,
+    pub timestamps: bool,
+}
+
+/// Load the tasks from a config file. Use `load_document` instead to read the
+/// settings from the same parse.
+#[cfg_attr(not(test), expect(dead_code, reason = "main also needs the settings"))]
+pub fn load_tasks(path: &str) -> Result<Vec<TaskEntry>, ConfigError> {
+    load_document(path)?.tasks()
+}
+
+fn parse_settings(section: &Section) -> Result<Settings, ConfigError> {
+    Ok(Settings {
+        max_parallel: parse_value(SETTINGS_SECTION, section, "max_parallel")?,
+        shell: parse_shell(SETTINGS_SECTION, section)?,
+        prefix: parse_value(SETTINGS_SECTION, section, "prefix")?.unwrap_or(false),
+        timestamps: parse_value(SETTINGS_SECTION, section, "timestamps")?.unwrap_or(false),
+    })
+}
+
+/// The keys of one task (or of the settings), with every value rendered as a
+/// string so that all formats share the same validation. Arrays are kept as
+/// lists, and TOML/YAML `env` tables are flattened into `env_<NAME>` keys.
+#[derive(Default)]
+struct Section {
+    values: Vec<(String, String)>,
+    lists: HashMap<String, Vec<String>>,
+}
+
+impl Section {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
 }
 
-/// Parse an INI file into task entries.
+/// A config file's settings and tasks, in file order.
+#[derive(Default)]
+pub struct Document {
+    settings: Option<Section>,
+    tasks: Vec<(String, Section)>,
+}
+
+impl Document {
+    /// The `[tequio]` settings, or the defaults if there are none.
+    pub fn settings(&self) -> Result<Settings, ConfigError> {
+        match &self.settings {
+            Some(section) => parse_settings(section),
+            None => Ok(Settings::default()),
+        }
+    }
+
+    /// The tasks, in file order.
+    pub fn tasks(self) -> Result<Vec<TaskEntry>, ConfigError> {
+        self.tasks
+            .into_iter()
+            .map(|(name, section)| parse_task(name, &section))
+            .collect()
+    }
+}
+
+/// Read and parse a config file.
 ///
-/// Each named section becomes a task. The section name is the task name,
-/// and `command`, `depends_on`, and `ready_check` are read from the section's keys.
-pub fn parse_ini(path: &str) -> Vec<TaskEntry> {
-    let ini = Ini::load_from_file(path)
-        .unwrap_or_else(|e| panic!("failed to read config file '{path}': {e}"));
-
-    ini.iter()
-        .filter_map(|(section, props)| {
-            let name = section?.to_string();
-            let command = props.get("command")?.to_string();
-            let work_dir = props.get("work_dir").map(|s| s.to_string());
-            let depends_on: Vec<String> = props
-                .get("depends_on")
-                .map(|s| s.split(',').map(|d| d.trim().to_string()).collect())
-                .unwrap_or_default();
-            let ready_check = props.get("ready_check").map(|s| s.to_string());
-            Some(TaskEntry {
-                name,
-                command,
-                work_dir,
-                depends_on,
-                ready_check,
-            })
+/// The format is picked from the file extension: `.toml`, `.yaml`, or `.yml`,
+/// and INI for anything else.
+pub fn load_document(path: &str) -> Result<Document, ConfigError> {
+    let text = std::fs::read_to_string(path).map_err(|e| read_failed(path, e))?;
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("toml") => parse_toml(path, &text),
+        Some("yaml" | "yml") => parse_yaml(path, &text),
+        _ => parse_ini(path, &text),
+    }
+}
+
+/// Each named INI section other than `[tequio]` is a task.
+fn parse_ini(path: &str, text: &str) -> Result<Document, ConfigError> {
+    let ini = Ini::load_from_str(text).map_err(|e| read_failed(path, e))?;
+    let mut doc = Document::default();
+    for (name, props) in ini.iter() {
+        let Some(name) = name else {
+            continue;
+        };
+        let section = Section {
+            values: props
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            lists: HashMap::new(),
+        };
+        if name == SETTINGS_SECTION {
+            doc.settings = Some(section);
+        } else {
+            doc.tasks.push((name.to_string(), section));
+        }
+    }
+    Ok(doc)
+}
+
+/// Settings live in a top-level `[tequio]` table and tasks in `[tasks.<name>]`.
+fn parse_toml(path: &str, text: &str) -> Result<Document, ConfigError> {
+    let root: toml::Table = text.parse().map_err(|e| read_failed(path, e))?;
+    let mut doc = Document::default();
+    if let Some(settings) = root.get(SETTINGS_SECTION) {
+        doc.settings = Some(toml_section(path, SETTINGS_SECTION, settings)?);
+    }
+    if let Some(tasks) = root.get(TASKS_TABLE) {
+        let tasks = tasks
+            .as_table()
+            .ok_or_else(|| read_failed(path, "'tasks' must be a table"))?;
+        for (name, task) in tasks {
+            doc.tasks.push((name.clone(), toml_section(path, name, task)?));
+        }
+    }
+    Ok(doc)
+}
+
+fn toml_section(path: &str, name: &str, value: &toml::Value) -> Result<Section, ConfigError> {
+    let table = value
+        .as_table()
+        .ok_or_else(|| read_failed(path, format!("'{name}' must be a table")))?;
+    let mut section = Section::default();
+    for (key, value) in table {
+        match value {
+            toml::Value::Table(env) if key == "env" => {
+                for (var, value) in env {
+                    let key = format!("env_{var}");
+                    let value = toml_scalar(name, &key, value)?;
+                    section.values.push((key, value));
+                }
+            }
+            toml::Value::Array(items) => {
+                let items = items
+                    .iter()
+                    .map(|item| toml_scalar(name, key, item))
+                    .collect::<Result<_, _>>()?;
+                section.lists.insert(key.clone(), items);
+            }
+            value => section.values.push((key.clone(), toml_scalar(name, key, value)?)),
+        }
+    }
+    Ok(section)
+}
+
+fn toml_scalar(task: &str, key: &str, value: &toml::Value) -> Result<String, ConfigError> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Array(_) | toml::Value::Table(_) => {
+            Err(invalid(task, key, &value.to_string()))
+        }
+        other => Ok(other.to_string()),
+    }
+}
+
+/// Same layout as TOML: a top-level `tequio` mapping and a `tasks` mapping.
+fn parse_yaml(path: &str, text: &str) -> Result<Document, ConfigError> {
+    let root: serde_yaml_ng::Value = serde_yaml_ng::from_str(text).map_err(|e| read_failed(path, e))?;
+    let mut doc = Document::default();
+    if root.is_null() {
+        return Ok(doc);
+    }
+    let root = root
+        .as_mapping()
+        .ok_or_else(|| read_failed(path, "expected a mapping at the top level"))?;
+    if let Some(settings) = root.get(SETTINGS_SECTION) {
+        doc.settings = Some(yaml_section(path, SETTINGS_SECTION, settings)?);
+    }
+    if let Some(tasks) = root.get(TASKS_TABLE) {
+        let tasks = tasks
+            .as_mapping()
+            .ok_or_else(|| read_failed(path, "'tasks' must be a mapping"))?;
+        for (name, task) in tasks {
+            let name = yaml_scalar(TASKS_TABLE, "task name", name)?;
+            let section = yaml_section(path, &name, task)?;
+            doc.tasks.push((name, section));
+        }
+    }
+    Ok(doc)
+}
+
+fn yaml_section(path: &str, name: &str, value: &serde_yaml_ng::Value) -> Result<Section, ConfigError> {
+    let mapping = value
+        .as_mapping()
+        .ok_or_else(|| read_failed(path, format!("'{name}' must be a mapping")))?;
+    let mut section = Section::default();
+    for (key, value) in mapping {
+        let key = yaml_scalar(name, "key", key)?;
+        match value {
+            serde_yaml_ng::Value::Null => {}
+            serde_yaml_ng::Value::Mapping(env) if key == "env" => {
+                for (var, value) in env {
+                    let key = format!("env_{}", yaml_scalar(name, "env", var)?);
+                    let value = yaml_scalar(name, &key, value)?;
+                    section.values.push((key, value));
+                }
+            }
+            serde_yaml_ng::Value::Sequence(items) => {
+                let items = items
+                    .iter()
+                    .map(|item| yaml_scalar(name, &key, item))
+                    .collect::<Result<_, _>>()?;
+                section.lists.insert(key, items);
+            }
+            value => {
+                let value = yaml_scalar(name, &key, value)?;
+                section.values.push((key, value));
+            }
+        }
+    }
+    Ok(section)
+}
+
+fn yaml_scalar(task: &str, key: &str, value: &serde_yaml_ng::Value) -> Result<String, ConfigError> {
+    match value {
+        serde_yaml_ng::Value::String(s) => Ok(s.clone()),
+        serde_yaml_ng::Value::Number(n) => Ok(n.to_string()),
+        serde_yaml_ng::Value::Bool(b) => Ok(b.to_string()),
+        other => {
+            let rendered = serde_yaml_ng::to_string(other).unwrap_or_default();
+            Err(invalid(task, key, rendered.trim()))
+        }
+    }
+}
+
+/// Build a task entry from its section.
+///
+/// `command`, `depends_on`, `depends_on_success`, and `ready_check` (or
+/// `ready_check_regex`) are read from the section's keys. `shell` overrides
+/// the global shell for this task (`none` execs directly). `env_<NAME>` keys
+/// set environment variables for the command, and `ready_port` marks the task
+/// ready once that local TCP port accepts a connection. `restart`,
+/// `max_restarts`, and `backoff_ms` control re-spawning on exit,
+/// `kill_timeout` is the grace period between SIGTERM and SIGKILL,
+/// `ready_timeout` bounds how long readiness may take, `timeout` bounds how
+/// long the process may run at all, and `watch` lists globs that restart it.
+fn parse_task(name: String, props: &Section) -> Result<TaskEntry, ConfigError> {
+    let command = props
+        .get("command")
+        .ok_or_else(|| ConfigError::MissingCommand { task: name.clone() })?;
+    let env = props
+        .iter()
+        .filter_map(|(key, value)| Some((key, key.strip_prefix("env_")?, value)))
+        .map(|(key, var, value)| {
+            let value = interpolate(&name, key, value, |var| std::env::var(var).ok())?;
+            Ok((var.to_string(), value))
         })
-        .collect()
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+    // The task's own environment takes precedence, as it would in its shell.
+    let expand = |key: &str, value: &str| {
+        interpolate(&name, key, value, |var| {
+            env.iter()
+                .find(|(key, _)| key == var)
+                .map(|(_, value)| value.clone())
+                .or_else(|| std::env::var(var).ok())
+        })
+    };
+    let command = expand("command", command)?;
+    let shell = parse_shell(&name, props)?;
+    let work_dir = props
+        .get("work_dir")
+        .map(|s| expand("work_dir", s))
+        .transpose()?;
+    let log_file = props.get("log_file").map(|s| s.to_string());
+    let depends_on = parse_list(props, "depends_on");
+    let depends_on_success = parse_list(props, "depends_on_success");
+    let ready_check = match (props.get("ready_check"), props.get("ready_check_regex")) {
+        (Some(_), Some(_)) => {
+            return Err(ConfigError::ConflictingKeys {
+                task: name,
+                first: "ready_check".to_string(),
+                second: "ready_check_regex".to_string(),
+            });
+        }
+        (Some(s), None) => Some(ReadyCheck::Literal(s.to_string())),
+        (None, Some(s)) => {
+            let re = Regex::new(s).map_err(|e| ConfigError::InvalidRegex {
+                task: name.clone(),
+                pattern: s.to_string(),
+                reason: e.to_string(),
+            })?;
+            Some(ReadyCheck::Regex(re))
+        }
+        (None, None) => None,
+    };
+    let ready_port = parse_value(&name, props, "ready_port")?;
+    let restart = match props.get("restart") {
+        Some(s) => RestartPolicy::parse(s).ok_or_else(|| invalid(&name, "restart", s))?,
+        None => RestartPolicy::No,
+    };
+    let max_restarts = parse_value(&name, props, "max_restarts")?;
+    let backoff_ms = parse_value(&name, props, "backoff_ms")?.unwrap_or(DEFAULT_BACKOFF_MS);
+    let kill_timeout =
+        parse_duration_value(&name, props, "kill_timeout")?.unwrap_or(DEFAULT_KILL_TIMEOUT);
+    let ready_timeout = parse_duration_value(&name, props, "ready_timeout")?;
+    let timeout = parse_duration_value(&name, props, "timeout")?;
+    let watch = parse_list(props, "watch");
+    if let Some(bad) = watch.iter().find(|p| glob::Pattern::new(p).is_err()) {
+        return Err(invalid(&name, "watch", bad));
+    }
+    Ok(TaskEntry {
+        name,
+        command,
+        shell,
+        work_dir,
+        env,
+        log_file,
+        depends_on,
+        depends_on_success,
+        ready_check,
+        ready_port,
+        restart,
+        max_restarts,
+        backoff_ms,
+        kill_timeout,
+        ready_timeout,
+        timeout,
+        watch,
+    })
+}
+
+/// Parse a list given as an array or as a comma-separated string, returning an
+/// empty list when the key is absent.
+fn parse_list(props: &Section, key: &str) -> Vec<String> {
+    if let Some(list) = props.lists.get(key) {
+        return list.clone();
+    }
+    props
+        .get(key)
+        .map(|s| s.split(',').map(|d| d.trim().to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Parse the optional `shell` key: a program name or path, or `none`/`false`.
+fn parse_shell(task: &str, props: &Section) -> Result<Option<Shell>, ConfigError> {
+    props
+        .get("shell")
+        .map(|s| Shell::parse(s).ok_or_else(|| invalid(task, "shell", s)))
+        .transpose()
+}
+
+/// Parse an optional key with `FromStr`, reporting malformed values as config errors.
+fn parse_value<T: FromStr>(
+    task: &str,
+    props: &Section,
+    key: &str,
+) -> Result<Option<T>, ConfigError> {
+    props
+        .get(key)
+        .map(|s| s.trim().parse::<T>().map_err(|_| invalid(task, key, s)))
+        .transpose()
+}
+
+/// Parse an optional duration key such as `500ms`, `5s`, or `2m`.
+fn parse_duration_value(
+    task: &str,
+    props: &Section,
+    key: &str,
+) -> Result<Option<Duration>, ConfigError> {
+    props
+        .get(key)
+        .map(|s| parse_duration(s).ok_or_else(|| invalid(task, key, s)))
+        .transpose()
+}
+
+/// Parse a duration with an `ms`, `s`, or `m` suffix. A bare number is seconds.
+/// Durations too long to be added to the current time are rejected, since
+/// they are used as deadlines.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (amount, unit) = value.split_at(
+        value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+    let amount: u64 = amount.parse().ok()?;
+    let duration = match unit.trim() {
+        "ms" => Duration::from_millis(amount),
+        "" | "s" => Duration::from_secs(amount),
+        "m" => Duration::from_secs(amount.checked_mul(60)?),
+        _ => return None,
+    };
+    std::time::Instant::now().checked_add(duration).map(|_| duration)
+}
+
+/// Expand `${VAR}` references using `lookup`. `$$` is an escaped `$`. A `$`
+/// not followed by `{`, or a `${...}` that is not a plain variable name (such
+/// as `${PORT:-3000}` or `${1}`), is left alone for the shell.
+fn interpolate(
+    task: &str,
+    key: &str,
+    value: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, ConfigError> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = after.strip_prefix('{') {
+            let end = after.find('}').ok_or_else(|| invalid(task, key, value))?;
+            let var = &after[..end];
+            if !is_variable_name(var) {
+                out.push_str(&rest[i..i + end + 3]);
+                rest = &after[end + 1..];
+                continue;
+            }
+            let expanded = lookup(var).ok_or_else(|| ConfigError::UndefinedVariable {
+                task: task.to_string(),
+                var: var.to_string(),
+            })?;
+            out.push_str(&expanded);
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Whether `s` matches `[A-Za-z_][A-Za-z0-9_]*`.
+fn is_variable_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn read_failed(path: &str, reason: impl fmt::Display) -> ConfigError {
+    ConfigError::ReadFailed {
+        path: path.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+fn invalid(task: &str, key: &str, value: &str) -> ConfigError {
+    ConfigError::InvalidValue {
+        task: task.to_string(),
+        key: key.to_string(),
+        value: value.to_string(),
+    }
 }
 
 /// Topological sort so dependencies come before dependents.
-/// Panics on cycles or missing dependency names.
-pub fn topo_sort(entries: Vec<TaskEntry>) -> Vec<TaskEntry> {
+/// Fails on cycles or missing dependency names.
+pub fn topo_sort(entries: Vec<TaskEntry>) -> Result<Vec<TaskEntry>, ConfigError> {
     let index_of: HashMap<&str, usize> = entries
         .iter()
         .enumerate()
@@ -54,10 +703,14 @@ pub fn topo_sort(entries: Vec<TaskEntry>) -> Vec<TaskEntry> {
     let mut adj: Vec<Vec<usize>> = vec![vec![]; n];
 
     for (i, entry) in entries.iter().enumerate() {
-        for dep in &entry.depends_on {
-            let &dep_idx = index_of.get(dep.as_str()).unwrap_or_else(|| {
-                panic!("task '{}' depends on unknown task '{}'", entry.name, dep)
-            });
+        for dep in entry.dependencies() {
+            let &dep_idx =
+                index_of
+                    .get(dep.as_str())
+                    .ok_or_else(|| ConfigError::UnknownDependency {
+                        task: entry.name.clone(),
+                        dep: dep.clone(),
+                    })?;
             adj[dep_idx].push(i);
             in_degree[i] += 1;
         }
@@ -77,19 +730,28 @@ pub fn topo_sort(entries: Vec<TaskEntry>) -> Vec<TaskEntry> {
     }
 
     if order.len() != n {
-        panic!("dependency cycle detected among tasks");
+        let nodes = (0..n)
+            .filter(|&i| in_degree[i] > 0)
+            .map(|i| entries[i].name.clone())
+            .collect();
+        return Err(ConfigError::Cycle { nodes });
     }
 
     let mut slots: Vec<Option<TaskEntry>> = entries.into_iter().map(Some).collect();
-    order
+    Ok(order
         .into_iter()
         .map(|i| slots[i].take().unwrap())
-        .collect()
+        .collect())
 }
 
-pub fn filter_tasks(entries: Vec<TaskEntry>, requested: &[String]) -> Vec<TaskEntry> {
+/// Keep only the `requested` tasks and everything they depend on, or every
+/// task if none are requested.
+pub fn filter_tasks(
+    entries: Vec<TaskEntry>,
+    requested: &[String],
+) -> Result<Vec<TaskEntry>, ConfigError> {
     if requested.is_empty() {
-        return entries;
+        return Ok(entries);
     }
 
     let name_to_idx: HashMap<&str, usize> = entries
@@ -98,11 +760,8 @@ pub fn filter_tasks(entries: Vec<TaskEntry>, requested: &[String]) -> Vec<TaskEn
         .map(|(i, e)| (e.name.as_str(), i))
         .collect();
 
-    for name in requested {
-        if !name_to_idx.contains_key(name.as_str()) {
-            eprintln!("error: task '{name}' not found in config");
-            std::process::exit(1);
-        }
+    if let Some(name) = requested.iter().find(|name| !name_to_idx.contains_key(name.as_str())) {
+        return Err(ConfigError::UnknownTask { task: name.clone() });
     }
 
     let mut reachable: HashSet<String> = requested.iter().cloned().collect();
@@ -110,15 +769,304 @@ pub fn filter_tasks(entries: Vec<TaskEntry>, requested: &[String]) -> Vec<TaskEn
 
     while let Some(name) = queue.pop_front() {
         let idx = name_to_idx[name.as_str()];
-        for dep in &entries[idx].depends_on {
+        for dep in entries[idx].dependencies() {
             if reachable.insert(dep.clone()) {
                 queue.push_back(dep.clone());
             }
         }
     }
 
-    entries
+    Ok(entries
         .into_iter()
         .filter(|e| reachable.contains(e.name.as_str()))
-        .collect()
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lookup(var: &str) -> Option<String> {
+        (var == "HOME").then(|| "/home/tequio".to_string())
+    }
+
+    #[test]
+    fn interpolate_expands_braced_variables() {
+        let out = interpolate("api", "command", "./server --root ${HOME}/data", lookup).unwrap();
+        assert_eq!(out, "./server --root /home/tequio/data");
+    }
+
+    #[test]
+    fn interpolate_leaves_unbraced_variables_alone() {
+        let out = interpolate("api", "command", "echo $HOME $", lookup).unwrap();
+        assert_eq!(out, "echo $HOME $");
+    }
+
+    #[test]
+    fn interpolate_unescapes_double_dollar() {
+        let out = interpolate("api", "command", "echo $${HOME} $$", lookup).unwrap();
+        assert_eq!(out, "echo ${HOME} $");
+    }
+
+    #[test]
+    fn interpolate_rejects_undefined_variables() {
+        let err = interpolate("api", "work_dir", "${PROJECT_ROOT}/api", lookup).unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::UndefinedVariable { ref task, ref var } if task == "api" && var == "PROJECT_ROOT"
+        ));
+    }
+
+    #[test]
+    fn interpolate_rejects_unterminated_references() {
+        let err = interpolate("api", "command", "echo ${HOME", lookup).unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidValue { .. }));
+    }
+
+    #[test]
+    fn interpolate_leaves_shell_expansions_alone() {
+        for value in ["${PORT:-3000}", "${1}", "${#arr}", "${}"] {
+            assert_eq!(interpolate("api", "command", value, lookup).unwrap(), value);
+        }
+        let out = interpolate("api", "command", "${HOME}:${1}/${HOME}", lookup).unwrap();
+        assert_eq!(out, "/home/tequio:${1}//home/tequio");
+    }
+
+    #[test]
+    fn task_env_is_used_before_the_process_environment() {
+        let section = Section {
+            values: vec![
+                ("command".to_string(), "./server --port ${PORT}".to_string()),
+                ("env_PORT".to_string(), "3000".to_string()),
+            ],
+            lists: HashMap::new(),
+        };
+        let entry = parse_task("api".to_string(), &section).unwrap();
+        assert_eq!(entry.command, "./server --port 3000");
+    }
+
+    fn argv(command: &str, shell: &str) -> Vec<String> {
+        let entry = TaskEntry {
+            shell: Shell::parse(shell),
+            ..TaskEntry::for_test("api", command)
+        };
+        entry.argv().unwrap()
+    }
+
+    #[test]
+    fn argv_follows_the_shell_setting() {
+        let direct = ["./server", "--name", "my app"];
+        assert_eq!(argv("./server --name 'my app'", "none"), direct);
+        assert_eq!(argv("./server --name 'my app'", "false"), direct);
+        let default = if cfg!(windows) { ["cmd", "/C", "echo hi"] } else { ["sh", "-c", "echo hi"] };
+        assert_eq!(argv("echo hi", "true"), default);
+        assert_eq!(argv("echo hi", "/bin/bash"), ["/bin/bash", "-c", "echo hi"]);
+        assert_eq!(argv("echo hi", "cmd"), ["cmd", "/C", "echo hi"]);
+    }
+
+    /// The same two tasks in each format, for checking they parse alike.
+    const INI: &str = "
+[tequio]
+max_parallel = 2
+
+[db]
+command = postgres
+ready_port = 5432
+
+[api]
+command = ./server
+depends_on = db, cache
+env_PORT = 3000
+timeout = 60s
+
+[cache]
+command = redis-server
+";
+
+    const TOML: &str = r#"
+[tequio]
+max_parallel = 2
+
+[tasks.db]
+command = "postgres"
+ready_port = 5432
+
+[tasks.api]
+command = "./server"
+depends_on = ["db", "cache"]
+timeout = "60s"
+
+[tasks.api.env]
+PORT = 3000
+
+[tasks.cache]
+command = "redis-server"
+"#;
+
+    const YAML: &str = "
+tequio:
+  max_parallel: 2
+tasks:
+  db:
+    command: postgres
+    ready_port: 5432
+  api:
+    command: ./server
+    depends_on: [db, cache]
+    env:
+      PORT: 3000
+    timeout: 60s
+  cache:
+    command: redis-server
+";
+
+    type Summary = (Option<usize>, Vec<(String, Vec<String>, Vec<(String, String)>)>);
+
+    fn summarize(doc: Document) -> Summary {
+        let settings = doc.settings.expect("settings section");
+        let max_parallel = parse_value(SETTINGS_SECTION, &settings, "max_parallel").unwrap();
+        let tasks = doc
+            .tasks
+            .into_iter()
+            .map(|(name, section)| parse_task(name, &section).unwrap())
+            .map(|entry| {
+                assert_eq!(entry.ready_port, (entry.name == "db").then_some(5432));
+                assert_eq!(entry.timeout, (entry.name == "api").then_some(Duration::from_secs(60)));
+                (entry.name, entry.depends_on, entry.env)
+            })
+            .collect();
+        (max_parallel, tasks)
+    }
+
+    #[test]
+    fn parse_duration_rejects_durations_that_overflow() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("5"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration(&format!("{}m", u64::MAX)), None);
+        assert_eq!(parse_duration(&format!("{}s", u64::MAX)), None);
+    }
+
+    #[test]
+    fn toml_and_yaml_parse_like_ini() {
+        let ini = summarize(parse_ini("tequio.ini", INI).unwrap());
+        assert_eq!(ini.0, Some(2));
+        assert_eq!(ini.1[1].0, "api");
+        assert_eq!(ini.1[1].1, ["db", "cache"]);
+        assert_eq!(ini.1[1].2, [("PORT".to_string(), "3000".to_string())]);
+
+        assert_eq!(summarize(parse_toml("tequio.toml", TOML).unwrap()), ini);
+        assert_eq!(summarize(parse_yaml("tequio.yaml", YAML).unwrap()), ini);
+    }
+
+    #[test]
+    fn load_tasks_picks_the_format_from_the_extension() {
+        let dir = std::env::temp_dir().join(format!("tequio-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, text) in [("tequio.conf", INI), ("tequio.TOML", TOML), ("tequio.yml", YAML)] {
+            let path = dir.join(file);
+            std::fs::write(&path, text).unwrap();
+            let tasks = load_tasks(path.to_str().unwrap()).unwrap();
+            let names: Vec<&str> = tasks.iter().map(|task| task.name.as_str()).collect();
+            assert_eq!(names, ["db", "api", "cache"], "{file}");
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn restart_policies_follow_the_exit_status() {
+        assert!(!RestartPolicy::No.should_restart(false));
+        assert!(!RestartPolicy::OnFailure.should_restart(true));
+        assert!(RestartPolicy::OnFailure.should_restart(false));
+        assert!(RestartPolicy::Always.should_restart(true));
+    }
+
+    #[test]
+    fn max_restarts_limits_the_restart_attempts() {
+        let entry = TaskEntry {
+            restart: RestartPolicy::Always,
+            max_restarts: Some(2),
+            ..TaskEntry::for_test("api", "./server")
+        };
+        assert!(entry.should_restart(true, 0));
+        assert!(entry.should_restart(false, 1));
+        assert!(!entry.should_restart(false, 2));
+
+        let unlimited = TaskEntry { max_restarts: None, ..entry };
+        assert!(unlimited.should_restart(true, 1000));
+    }
+
+    fn task(name: &str, depends_on: &[&str]) -> TaskEntry {
+        TaskEntry {
+            depends_on: depends_on.iter().map(|dep| dep.to_string()).collect(),
+            ..TaskEntry::for_test(name, "true")
+        }
+    }
+
+    fn names(entries: &[TaskEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn tasks_without_a_command_are_rejected() {
+        let doc = parse_ini("tequio.ini", "[api]\nready_port = 3000\n").unwrap();
+        let Err(err) = doc.tasks() else {
+            panic!("a task without a command should not parse");
+        };
+        assert!(matches!(err, ConfigError::MissingCommand { ref task } if task == "api"));
+    }
+
+    #[test]
+    fn topo_sort_orders_dependencies_first() {
+        let sorted = topo_sort(vec![task("api", &["db"]), task("db", &[])]).unwrap();
+        assert_eq!(names(&sorted), ["db", "api"]);
+    }
+
+    #[test]
+    fn topo_sort_rejects_unknown_dependencies() {
+        let Err(err) = topo_sort(vec![task("api", &["db"])]) else {
+            panic!("an unknown dependency should not sort");
+        };
+        assert!(matches!(
+            err,
+            ConfigError::UnknownDependency { ref task, ref dep } if task == "api" && dep == "db"
+        ));
+    }
+
+    #[test]
+    fn topo_sort_lists_the_tasks_left_in_a_cycle() {
+        let entries = vec![
+            task("db", &[]),
+            task("api", &["db", "worker"]),
+            task("worker", &["api"]),
+            task("web", &["api"]),
+        ];
+        let Err(ConfigError::Cycle { nodes }) = topo_sort(entries) else {
+            panic!("a cycle should not sort");
+        };
+        assert_eq!(nodes, ["api", "worker", "web"]);
+    }
+
+    #[test]
+    fn filter_tasks_keeps_requested_tasks_and_their_dependencies() {
+        let entries = vec![task("db", &[]), task("api", &["db"]), task("web", &[])];
+        let filtered = filter_tasks(entries, &["api".to_string()]).unwrap();
+        assert_eq!(names(&filtered), ["db", "api"]);
+    }
+
+    #[test]
+    fn filter_tasks_rejects_unknown_tasks() {
+        let Err(err) = filter_tasks(vec![task("db", &[])], &["api".to_string()]) else {
+            panic!("an unknown task should not be selected");
+        };
+        assert!(matches!(err, ConfigError::UnknownTask { ref task } if task == "api"));
+    }
+
+    #[test]
+    fn nested_values_are_rejected_outside_lists() {
+        let Err(err) = parse_toml("tequio.toml", "[tasks.api]\ncommand = { run = \"x\" }\n") else {
+            panic!("a table command should not parse");
+        };
+        assert!(matches!(err, ConfigError::InvalidValue { ref key, .. } if key == "command"));
+    }
 }
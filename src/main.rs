@@ -1,31 +1,36 @@
-//! Spawn real processes from an INI config and display them in a TUI.
-//! Use arrow keys to switch between tasks.
+//! Spawn real processes from an INI, TOML, or YAML config and display them in a TUI.
+//! Use arrow keys to switch between tasks and `r` to restart the selected one.
 
 mod config;
 mod pidfile;
 mod runner;
+mod watcher;
 
 use std::collections::HashMap;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 use std::env;
 
 use clap::Parser;
-use tokio::sync::{Mutex, watch};
+use tokio::sync::{Mutex, Semaphore, mpsc, watch};
+use tokio::task::{JoinError, JoinSet};
 use tokio::time::sleep;
 use turbopath::AbsoluteSystemPathBuf;
 use turborepo_ui::{
-    ColorConfig,
+    ColorConfig, ColorSelector,
     tui::{self, TuiSender},
 };
 
-use config::{parse_ini, topo_sort, filter_tasks};
+use config::{TaskEntry, load_document, topo_sort, filter_tasks};
 use pidfile::PidFile;
-use runner::run_task;
+use runner::{Dependency, LinePrefix, RestartRequest, TaskContext, TaskState, run_task};
 
 #[derive(Parser)]
 struct Cli {
-    /// Path to INI config file
+    /// Path to the config file (`.ini`, `.toml`, `.yaml`, or `.yml`)
     #[arg(long, short, default_value = "tequio.ini")]
     config: String,
 
@@ -33,6 +38,22 @@ struct Cli {
     #[arg(long)]
     stop: bool,
 
+    /// Write each task's output to `<dir>/<task>.log`
+    #[arg(long)]
+    log_dir: Option<String>,
+
+    /// Maximum number of tasks running at once (default: unlimited)
+    #[arg(long)]
+    max_parallel: Option<NonZeroUsize>,
+
+    /// Prefix each output line with the task name
+    #[arg(long)]
+    prefix: bool,
+
+    /// Prefix each output line with the local time it was printed
+    #[arg(long)]
+    timestamps: bool,
+
     /// Tasks to run (default: all)
     tasks: Vec<String>,
 }
@@ -48,26 +69,67 @@ async fn main() -> Result<(), turborepo_ui::Error> {
         return Ok(());
     }
 
-    let entries = parse_ini(&cli.config);
+    let loaded = load_document(&cli.config)
+        .and_then(|document| Ok((document.settings()?, topo_sort(document.tasks()?)?)));
+    let (settings, entries) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    };
     if entries.is_empty() {
         eprintln!("no tasks found in '{}'", cli.config);
         std::process::exit(1);
     }
 
-    let entries = filter_tasks(entries, &cli.tasks);
+    let mut entries = match filter_tasks(entries, &cli.tasks) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    for entry in entries.iter_mut() {
+        if entry.shell.is_none() {
+            entry.shell = settings.shell.clone();
+        }
+        if let Err(e) = entry.argv() {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(dir) = &cli.log_dir {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            eprintln!("error: failed to create log directory '{dir}': {e}");
+            std::process::exit(1);
+        }
+        for entry in entries.iter_mut().filter(|e| e.log_file.is_none()) {
+            let path = Path::new(dir).join(format!("{}.log", entry.name));
+            entry.log_file = Some(path.to_string_lossy().into_owned());
+        }
+    }
+
+    // Each run of a task appends to its log, so start every log empty here.
+    // Failures show up in the task's pane when it opens the log.
+    for path in entries.iter().filter_map(|e| e.log_file.as_deref()) {
+        std::fs::File::create(path).ok();
+    }
 
     let mut pidfile = PidFile::new();
     pidfile.load_and_kill_existing().await;
     let pidfile = Arc::new(Mutex::new(pidfile));
 
-    let entries = topo_sort(entries);
     let task_names: Vec<String> = entries.iter().map(|e| e.name.clone()).collect();
     let color_config = ColorConfig::infer();
     let repo_root = AbsoluteSystemPathBuf::new(std::env::current_dir().unwrap().to_str().unwrap())
         .expect("cwd is absolute");
 
-    let (sender, receiver) = TuiSender::new();
+    let (sender, mut receiver) = TuiSender::new();
     let stop_sender = sender.clone();
+    let mut restart_rx = receiver.restart_requests();
 
     // Spawn the TUI render loop.
     let mut tui_handle = tokio::spawn(async move {
@@ -77,49 +139,81 @@ async fn main() -> Result<(), turborepo_ui::Error> {
     // Shutdown signal: when true, all tasks should kill their children and exit.
     let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
+    // Caps how many task processes run at once; FIFO so queued tasks start in order.
+    let limiter = cli
+        .max_parallel
+        .or(settings.max_parallel)
+        .map(|n| Arc::new(Semaphore::new(n.get())));
+
     // Build ready-signal channels for each task.
-    let mut ready_txs: HashMap<String, watch::Sender<bool>> = HashMap::new();
-    let mut ready_rxs: HashMap<String, watch::Receiver<bool>> = HashMap::new();
+    let mut ready_txs: HashMap<String, Arc<watch::Sender<TaskState>>> = HashMap::new();
+    let mut ready_rxs: HashMap<String, watch::Receiver<TaskState>> = HashMap::new();
     for entry in &entries {
-        let (tx, rx) = watch::channel(false);
-        ready_txs.insert(entry.name.clone(), tx);
+        let (tx, rx) = watch::channel(TaskState::Pending);
+        ready_txs.insert(entry.name.clone(), Arc::new(tx));
         ready_rxs.insert(entry.name.clone(), rx);
     }
 
-    // Spawn all tasks concurrently (dependency waiting happens inside run_task).
-    let handles: Vec<_> = entries
-        .into_iter()
+    // Per-task line prefixes, each in its own color; empty when prefixing is off.
+    let show_name = cli.prefix || settings.prefix;
+    let timestamps = cli.timestamps || settings.timestamps;
+    let colors = ColorSelector::default();
+    let prefixes: HashMap<String, LinePrefix> = entries
+        .iter()
+        .filter(|_| show_name || timestamps)
         .map(|entry| {
-            let s = sender.clone();
-            let ready_tx = ready_txs.remove(&entry.name).unwrap();
-            let dep_rxs: Vec<watch::Receiver<bool>> = entry
-                .depends_on
-                .iter()
-                .map(|dep| ready_rxs.get(dep).expect("dep must exist").clone())
-                .collect();
-            let shutdown = shutdown_rx.clone();
-            let pf = pidfile.clone();
-
-            // Normalize the working directory of every task
-            let current_dir = resolve_work_dir(entry.work_dir.as_deref());
-
-            tokio::spawn(async move {
-                run_task(s, entry.name, entry.command, current_dir, entry.ready_check, ready_tx, dep_rxs, shutdown, pf).await;
-            })
+            let style = colors.color_for_key(&entry.name).clone();
+            let prefix = LinePrefix::new(&entry.name, show_name, timestamps, style, color_config);
+            (entry.name.clone(), prefix)
         })
         .collect();
 
+    let launcher = Launcher {
+        context: TaskContext {
+            sender: sender.clone(),
+            shutdown_rx,
+            pidfile: pidfile.clone(),
+            limiter,
+        },
+        entries: entries.iter().map(|e| (e.name.clone(), e.clone())).collect(),
+        ready_txs,
+        ready_rxs,
+        prefixes,
+    };
+
+    // Spawn all tasks concurrently (dependency waiting happens inside run_task).
+    let mut tasks = JoinSet::new();
+    let mut restart_txs: HashMap<String, mpsc::UnboundedSender<RestartRequest>> = entries
+        .iter()
+        .map(|entry| (entry.name.clone(), launcher.spawn(&mut tasks, &entry.name)))
+        .collect();
+    let mut exit_codes: HashMap<String, i32> = HashMap::new();
+
+    // Restart tasks when the files matching their `watch` patterns change.
+    let (changes_tx, mut changes_rx) = mpsc::unbounded_channel();
+    let mut watchers = Vec::new();
+    for entry in entries.iter().filter(|e| !e.watch.is_empty()) {
+        let work_dir = resolve_work_dir(entry.work_dir.as_deref());
+        let watched = watcher::watch_task(
+            entry.name.clone(),
+            Path::new(&work_dir),
+            &entry.watch,
+            changes_tx.clone(),
+        );
+        match watched {
+            Ok(watcher) => watchers.push(watcher),
+            Err(e) => {
+                writeln!(sender.task(entry.name.clone()), "failed to watch files: {e}").ok();
+            }
+        }
+    }
+
     let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
 
     #[cfg(unix)]
     let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).ok();
 
-    let all_tasks = async move {
-        for handle in handles {
-            handle.await.ok();
-        }
-    };
-
     #[cfg(unix)]
     let sigterm_fut = async {
         if let Some(ref mut sig) = sigterm {
@@ -131,38 +225,188 @@ async fn main() -> Result<(), turborepo_ui::Error> {
 
     #[cfg(not(unix))]
     let sigterm_fut = std::future::pending::<()>();
+    tokio::pin!(sigterm_fut);
 
-    // Race between all tasks completing, TUI exit, Ctrl+C, and SIGTERM.
-    tokio::select! {
-        _ = all_tasks => {
-            sleep(Duration::from_secs(2)).await;
-            stop_sender.stop().await;
+    // Race between all tasks completing, TUI exit, Ctrl+C, and SIGTERM, while
+    // routing restart requests from the TUI and file watchers to their tasks.
+    // With files being watched, tequio keeps running after every task exits.
+    let mut tui_done = false;
+    let finished = loop {
+        tokio::select! {
+            joined = tasks.join_next(), if !tasks.is_empty() || watchers.is_empty() => match joined {
+                Some(joined) => record_exit(&mut exit_codes, joined),
+                None => break true,
+            },
+            Some(name) = restart_rx.recv() => {
+                launcher.restart(&mut tasks, &mut restart_txs, &name, None);
+            }
+            Some((name, ack)) = changes_rx.recv() => {
+                // Tasks waiting on this one's success run again once it has
+                // rebuilt; tasks that only waited for it to be ready are left alone.
+                let rerun: Vec<String> = std::iter::once(name.clone())
+                    .chain(launcher.success_dependents(&name))
+                    .collect();
+                for name in &rerun {
+                    launcher.ready_txs[name].send_replace(TaskState::Pending);
+                }
+                let mut ack = Some(ack);
+                for name in &rerun {
+                    launcher.restart(&mut tasks, &mut restart_txs, name, ack.take());
+                }
+            }
+            _ = &mut tui_handle => {
+                tui_done = true;
+                break false;
+            }
+            _ = &mut ctrl_c => break false,
+            _ = &mut sigterm_fut => break false,
         }
-        _ = &mut tui_handle => {
-            shutdown_tx.send(true).ok();
-        }
-        _ = ctrl_c => {
-            shutdown_tx.send(true).ok();
-            stop_sender.stop().await;
-        }
-        _ = sigterm_fut => {
-            shutdown_tx.send(true).ok();
-            stop_sender.stop().await;
+    };
+
+    drop(watchers);
+
+    if finished {
+        sleep(Duration::from_secs(2)).await;
+    } else {
+        shutdown_tx.send(true).ok();
+
+        // Let every task run its kill path; a second Ctrl+C skips the wait.
+        let all_tasks = async {
+            while let Some(joined) = tasks.join_next().await {
+                record_exit(&mut exit_codes, joined);
+            }
+        };
+        tokio::select! {
+            _ = all_tasks => {}
+            _ = tokio::signal::ctrl_c() => {
+                stop_sender.stop().await;
+                pidfile.lock().await.cleanup().await;
+                std::process::exit(130);
+            }
         }
     }
-
-    // Wait for all processes to be killed before cleanup.
-    sleep(Duration::from_millis(500)).await;
+    stop_sender.stop().await;
+    drop(launcher);
 
     // Clean up pidfile (processes should be gone by now).
     if let Some(pf) = Arc::try_unwrap(pidfile).ok() {
         pf.into_inner().cleanup().await;
     }
 
-    let _ = tui_handle.await;
+    if !tui_done {
+        let _ = tui_handle.await;
+    }
+
+    // Exit with the highest exit code reported by any task.
+    let exit_code = exit_codes.into_values().max().unwrap_or(0);
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
     Ok(())
 }
 
+/// Everything needed to (re)launch a task with `run_task`.
+struct Launcher {
+    context: TaskContext,
+    entries: HashMap<String, TaskEntry>,
+    ready_txs: HashMap<String, Arc<watch::Sender<TaskState>>>,
+    ready_rxs: HashMap<String, watch::Receiver<TaskState>>,
+    prefixes: HashMap<String, LinePrefix>,
+}
+
+impl Launcher {
+    /// Spawn a task, returning the channel used to ask it to restart.
+    fn spawn(
+        &self,
+        tasks: &mut JoinSet<(String, i32)>,
+        name: &str,
+    ) -> mpsc::UnboundedSender<RestartRequest> {
+        let entry = self.entries[name].clone();
+        let ready_deps = entry.depends_on.iter().map(|dep| (dep, false));
+        let success_deps = entry.depends_on_success.iter().map(|dep| (dep, true));
+        let deps: Vec<Dependency> = ready_deps
+            .chain(success_deps)
+            .map(|(dep, until_success)| Dependency {
+                name: dep.clone(),
+                state: self.ready_rxs.get(dep).expect("dep must exist").clone(),
+                until_success,
+            })
+            .collect();
+        let (restart_tx, restart_rx) = mpsc::unbounded_channel();
+        let context = self.context.clone();
+        let ready_tx = self.ready_txs[name].clone();
+        let prefix = self.prefixes.get(name).cloned();
+
+        // Normalize the working directory of every task
+        let current_dir = resolve_work_dir(entry.work_dir.as_deref());
+
+        tasks.spawn(async move {
+            let name = entry.name.clone();
+            let exit_code =
+                run_task(context, entry, current_dir, ready_tx, deps, restart_rx, prefix).await;
+            (name, exit_code)
+        });
+        restart_tx
+    }
+
+    /// Restart a task: signal it if it is still running, or launch it again if
+    /// it has exited. `ack` is notified once the new process has been spawned.
+    fn restart(
+        &self,
+        tasks: &mut JoinSet<(String, i32)>,
+        restart_txs: &mut HashMap<String, mpsc::UnboundedSender<RestartRequest>>,
+        name: &str,
+        ack: RestartRequest,
+    ) {
+        let ack = match restart_txs.get(name).map(|tx| tx.send(ack)) {
+            Some(Ok(())) => return,
+            Some(Err(returned)) => returned.0,
+            None => return,
+        };
+        if self.entries.contains_key(name) {
+            let restart_tx = self.relaunch(tasks, name);
+            // A fresh task picks this up before spawning its first process.
+            restart_tx.send(ack).ok();
+            restart_txs.insert(name.to_string(), restart_tx);
+        }
+    }
+
+    /// Every task that waits, directly or through others, for `name` to succeed.
+    fn success_dependents(&self, name: &str) -> Vec<String> {
+        let mut found: Vec<String> = Vec::new();
+        let mut queue = vec![name.to_string()];
+        while let Some(upstream) = queue.pop() {
+            for entry in self.entries.values() {
+                if entry.depends_on_success.contains(&upstream)
+                    && entry.name != name
+                    && !found.contains(&entry.name)
+                {
+                    found.push(entry.name.clone());
+                    queue.push(entry.name.clone());
+                }
+            }
+        }
+        found
+    }
+
+    /// Launch a task again after it has exited. Its dependents are left alone.
+    fn relaunch(
+        &self,
+        tasks: &mut JoinSet<(String, i32)>,
+        name: &str,
+    ) -> mpsc::UnboundedSender<RestartRequest> {
+        self.context.sender.restart_tasks(vec![name.to_string()]).ok();
+        self.ready_txs[name].send_replace(TaskState::Pending);
+        self.spawn(tasks, name)
+    }
+}
+
+fn record_exit(exit_codes: &mut HashMap<String, i32>, joined: Result<(String, i32), JoinError>) {
+    // A task that panicked can't report its name, but still counts as a failure.
+    let (name, exit_code) = joined.unwrap_or_else(|_| (String::new(), 1));
+    exit_codes.insert(name, exit_code);
+}
+
 fn resolve_work_dir(entry_work_dir: Option<&str>) -> String {
     let current_dir_pathbuf = env::current_dir().expect("Failed to get current directory");
     let current_dir_string: String = current_dir_pathbuf
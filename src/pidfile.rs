@@ -1,35 +1,70 @@
 use std::collections::HashSet;
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
 
+/// Pidfiles are named `tequio-pids-<owner pid>.txt` so concurrent instances
+/// never share one.
+const FILE_PREFIX: &str = "tequio-pids-";
+const FILE_SUFFIX: &str = ".txt";
+
+/// The pidfile shared by every instance before pidfiles were per instance.
+const LEGACY_FILE_NAME: &str = "tequio-pids.txt";
+
+/// The pidfile of a running tequio stays locked until it exits, so a pidfile
+/// that can be locked was left behind by one that did not clean up.
 pub struct PidFile {
     path: PathBuf,
     pids: HashSet<u32>,
+    /// Created and locked on the first registered pid.
+    file: Option<fs::File>,
 }
 
 impl PidFile {
     pub fn new() -> Self {
-        let path = std::env::temp_dir().join("tequio-pids.txt");
-        Self { path, pids: HashSet::new() }
+        Self::in_dir(&std::env::temp_dir())
+    }
+
+    /// A pidfile for this process in `dir`, which is also where stale
+    /// pidfiles from other instances are looked for.
+    pub fn in_dir(dir: &Path) -> Self {
+        let path = dir.join(format!("{FILE_PREFIX}{}{FILE_SUFFIX}", std::process::id()));
+        Self { path, pids: HashSet::new(), file: None }
     }
 
+    /// Kill the tasks left behind by tequio instances that exited without
+    /// cleaning up, including those listed in the legacy shared pidfile.
+    /// Pidfiles still locked by their owner are left alone so other sessions
+    /// keep their tasks.
     pub async fn load_and_kill_existing(&mut self) -> usize {
-        if !self.path.exists() {
+        let Some(Ok(dir)) = self.path.parent().map(fs::read_dir) else {
             return 0;
-        }
-        let contents = match fs::read_to_string(&self.path) {
-            Ok(c) => c,
-            Err(_) => return 0,
         };
         let mut count = 0;
-        for line in contents.lines() {
-            if let Ok(pid) = line.trim().parse::<u32>() {
-                Self::kill_pid_tree(pid).await;
-                count += 1;
+        for path in dir.flatten().map(|entry| entry.path()) {
+            let legacy = path.file_name().is_some_and(|name| name == LEGACY_FILE_NAME);
+            if path == self.path || !(legacy || is_pidfile(&path)) {
+                continue;
+            }
+            let Ok(mut file) = fs::File::open(&path) else {
+                continue;
+            };
+            // Older versions never locked the shared pidfile.
+            if !legacy && file.try_lock().is_err() {
+                continue;
+            }
+            let mut contents = String::new();
+            if file.read_to_string(&mut contents).is_err() {
+                continue;
             }
+            for line in contents.lines() {
+                if let Ok(pid) = line.trim().parse::<u32>() {
+                    Self::kill_pid_tree(pid).await;
+                    count += 1;
+                }
+            }
+            let _ = fs::remove_file(&path);
         }
-        let _ = fs::remove_file(&self.path);
         count
     }
 
@@ -40,11 +75,7 @@ impl PidFile {
 
     pub fn unregister(&mut self, pid: u32) {
         self.pids.remove(&pid);
-        if self.pids.is_empty() {
-            let _ = fs::remove_file(&self.path);
-        } else {
-            self.write();
-        }
+        self.write();
     }
 
     pub async fn cleanup(&mut self) {
@@ -52,19 +83,124 @@ impl PidFile {
             Self::kill_pid_tree(pid).await;
         }
         let _ = fs::remove_file(&self.path);
+        self.file = None;
     }
 
-    fn write(&self) {
-        if let Ok(mut file) = fs::File::create(&self.path) {
-            for pid in &self.pids {
-                let _ = writeln!(file, "{}", pid);
-            }
+    /// Rewrite the pidfile in place, so the lock on it is kept.
+    fn write(&mut self) {
+        if self.file.is_none() {
+            self.file = fs::File::create(&self.path)
+                .ok()
+                .filter(|file| file.try_lock().is_ok());
+        }
+        if let Some(file) = self.file.as_mut() {
+            let contents: String = self.pids.iter().map(|pid| format!("{pid}\n")).collect();
+            let _ = file
+                .set_len(0)
+                .and_then(|()| file.rewind())
+                .and_then(|()| file.write_all(contents.as_bytes()));
         }
     }
 
-    async fn kill_pid_tree(pid: u32) {
+    pub async fn kill_pid_tree(pid: u32) {
         let _ = kill_tree::tokio::kill_tree(pid).await;
     }
+
+    /// Ask every process in the tree rooted at `pid` to exit with SIGTERM,
+    /// returning the processes that were signalled. The tree is walked before
+    /// signalling, so descendants stay known after `pid` exits and they are
+    /// reparented.
+    pub async fn term_pid_tree(pid: u32) -> Vec<u32> {
+        let config = kill_tree::Config {
+            signal: "SIGTERM".to_string(),
+            ..Default::default()
+        };
+        match kill_tree::tokio::kill_tree_with_config(pid, &config).await {
+            Ok(outputs) => outputs
+                .into_iter()
+                .filter_map(|output| match output {
+                    kill_tree::Output::Killed { process_id, .. } => Some(process_id),
+                    _ => None,
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Kill every process in the tree rooted at `pid` with SIGKILL.
+    pub async fn kill_pid_tree_forcefully(pid: u32) {
+        let config = kill_tree::Config {
+            signal: "SIGKILL".to_string(),
+            ..Default::default()
+        };
+        let _ = kill_tree::tokio::kill_tree_with_config(pid, &config).await;
+    }
+
+    /// Whether any of `pids` is still running.
+    pub fn any_running(pids: &[u32]) -> bool {
+        pids.iter().any(|&pid| process_running(pid))
+    }
+
+    /// Send SIGKILL to each of `pids` that is still running.
+    #[cfg(unix)]
+    pub fn kill_pids(pids: &[u32]) {
+        use nix::sys::signal::{Signal, kill};
+        use nix::unistd::Pid;
+
+        for &pid in pids.iter().filter(|&&pid| process_running(pid)) {
+            if let Ok(pid) = i32::try_from(pid) {
+                let _ = kill(Pid::from_raw(pid), Signal::SIGKILL);
+            }
+        }
+    }
+
+    /// Windows has no SIGTERM: kill_tree already terminated the processes.
+    #[cfg(windows)]
+    pub fn kill_pids(_pids: &[u32]) {}
+}
+
+/// Whether `path` is named like a per-instance pidfile.
+fn is_pidfile(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix(FILE_PREFIX))
+        .and_then(|name| name.strip_suffix(FILE_SUFFIX))
+        .is_some_and(|owner| owner.parse::<u32>().is_ok())
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    use nix::errno::Errno;
+    use nix::sys::signal::kill;
+    use nix::unistd::Pid;
+
+    let Ok(pid) = i32::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks that the process exists; EPERM means it does but
+    // belongs to someone else.
+    matches!(kill(Pid::from_raw(pid), None), Ok(()) | Err(Errno::EPERM))
+}
+
+/// Like `process_alive`, but a zombie waiting to be reaped counts as exited.
+#[cfg(unix)]
+fn process_running(pid: u32) -> bool {
+    if !process_alive(pid) {
+        return false;
+    }
+    // The state follows the parenthesised command name in /proc/<pid>/stat.
+    #[cfg(target_os = "linux")]
+    if let Ok(stat) = fs::read_to_string(format!("/proc/{pid}/stat"))
+        && let Some((_, rest)) = stat.rsplit_once(')')
+    {
+        return !rest.trim_start().starts_with('Z');
+    }
+    true
+}
+
+#[cfg(windows)]
+fn process_running(_pid: u32) -> bool {
+    false
 }
 
 impl Drop for PidFile {
@@ -83,3 +219,67 @@ impl Drop for PidFile {
         let _ = fs::remove_file(&self.path);
     }
 }
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    fn sleeper() -> std::process::Child {
+        std::process::Command::new("sleep").arg("30").spawn().unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_unlocked_pidfiles_are_reaped() {
+        // A directory of its own, so other pidfiles on the machine are untouched.
+        let dir = std::env::temp_dir().join(format!("tequio-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut task = sleeper();
+        // Named after a running process, as when a dead owner's pid is reused.
+        let path = dir.join(format!("{FILE_PREFIX}1{FILE_SUFFIX}"));
+        let mut owner = fs::File::create(&path).unwrap();
+        owner.try_lock().unwrap();
+        writeln!(owner, "{}", task.id()).unwrap();
+
+        let mut pidfile = PidFile::in_dir(&dir);
+        pidfile.load_and_kill_existing().await;
+        assert!(task.try_wait().unwrap().is_none(), "live session's task was killed");
+        assert!(path.exists());
+
+        drop(owner);
+        pidfile.load_and_kill_existing().await;
+        assert!(!task.wait().unwrap().success());
+        assert!(!path.exists());
+
+        drop(pidfile);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_legacy_shared_pidfile_is_reaped() {
+        let dir = std::env::temp_dir().join(format!("tequio-legacy-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut task = sleeper();
+        let path = dir.join(LEGACY_FILE_NAME);
+        fs::write(&path, format!("{}\n", task.id())).unwrap();
+
+        assert_eq!(PidFile::in_dir(&dir).load_and_kill_existing().await, 1);
+        assert!(!task.wait().unwrap().success());
+        assert!(!path.exists());
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_running_instance_keeps_its_pidfile_locked() {
+        let dir = std::env::temp_dir().join(format!("tequio-lock-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut pidfile = PidFile::in_dir(&dir);
+        pidfile.register(1);
+        pidfile.unregister(1);
+
+        let file = fs::File::open(&pidfile.path).unwrap();
+        assert!(file.try_lock().is_err());
+        drop(file);
+        drop(pidfile);
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -1,161 +1,865 @@
+use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::sync::Arc;
+use std::time::Duration;
 
+use chrono::{DateTime, Local};
+use console::Style;
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command;
-use tokio::sync::{Mutex, watch};
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep};
+use turborepo_ui::ColorConfig;
+use turborepo_ui::sender::TaskSender;
 use turborepo_ui::tui::{self, TuiSender, event::OutputLogs};
 
+use crate::config::TaskEntry;
 use crate::pidfile::PidFile;
 
+/// How long to wait for a process to exit after SIGKILL before giving up on it.
+const KILL_WAIT: Duration = Duration::from_secs(1);
+
+/// How long to keep reading a process's output once it has exited. Background
+/// processes it started can hold the pipes open indefinitely.
+const OUTPUT_WAIT: Duration = Duration::from_millis(500);
+
+/// Readiness of a task as seen by the tasks that depend on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Pending,
+    Ready,
+    Succeeded,
+    Failed,
+}
+
+/// A request to restart a task. The sender, if any, is notified once the
+/// replacement process has been spawned, or dropped if it never is.
+pub type RestartRequest = Option<oneshot::Sender<()>>;
+
+/// An upstream task that must reach a given state before this one starts.
+pub struct Dependency {
+    pub name: String,
+    pub state: watch::Receiver<TaskState>,
+    /// Wait for the upstream to exit successfully instead of just being ready.
+    pub until_success: bool,
+}
+
+/// Handles shared by every task.
+#[derive(Clone)]
+pub struct TaskContext {
+    pub sender: TuiSender,
+    /// Becomes true when all tasks should kill their children and exit.
+    pub shutdown_rx: watch::Receiver<bool>,
+    pub pidfile: Arc<Mutex<PidFile>>,
+    /// Caps how many task processes run at once, for `--max-parallel`.
+    pub limiter: Option<Arc<Semaphore>>,
+}
+
+/// Run a task to completion and return its exit code. Tasks stopped by a
+/// shutdown report 0; tasks that fail without an exit code of their own, or
+/// are skipped because a dependency failed, report 1.
 pub async fn run_task(
-    sender: TuiSender,
-    name: String,
-    command: String,
+    context: TaskContext,
+    entry: TaskEntry,
     work_dir: String,
-    ready_check: Option<String>,
-    ready_tx: watch::Sender<bool>,
-    dep_rxs: Vec<watch::Receiver<bool>>,
-    mut shutdown_rx: watch::Receiver<bool>,
-    pidfile: Arc<Mutex<PidFile>>,
-) {
+    ready_tx: Arc<watch::Sender<TaskState>>,
+    mut deps: Vec<Dependency>,
+    mut restart_rx: mpsc::UnboundedReceiver<RestartRequest>,
+    prefix: Option<LinePrefix>,
+) -> i32 {
+    let TaskContext {
+        sender,
+        mut shutdown_rx,
+        pidfile,
+        limiter,
+    } = context;
+    let name = entry.name.clone();
     let mut task = sender.task(name.clone());
     task.start(OutputLogs::Full);
+    let log = entry
+        .log_file
+        .as_deref()
+        .and_then(|path| LogFile::open(path, &mut task));
 
-    if !dep_rxs.is_empty() {
-        sender.status(
-            name.clone(),
-            "waiting".into(),
-            tui::event::CacheResult::Miss,
-        );
-        let wait_all = async {
-            for mut rx in dep_rxs {
-                rx.wait_for(|&ready| ready).await.ok();
-            }
-        };
-        tokio::select! {
-            _ = wait_all => {}
-            _ = shutdown_rx.wait_for(|&v| v) => {
-                ready_tx.send(true).ok();
-                task.failed();
-                return;
+    let argv = match entry.argv() {
+        Ok(argv) => argv,
+        Err(e) => {
+            writeln!(task, "{e}").ok();
+            mark_failed(&ready_tx, &task);
+            return 1;
+        }
+    };
+
+    let mut attempt: u32 = 0;
+    // Acknowledged once the next child has been spawned.
+    let mut pending_acks: Vec<oneshot::Sender<()>> = Vec::new();
+
+    loop {
+        // Dependencies are checked again on manual restarts, so a task that is
+        // restarted along with a rebuilding upstream waits for it to finish.
+        if attempt == 0 && !deps.is_empty() {
+            sender.status(
+                name.clone(),
+                "waiting".into(),
+                tui::event::CacheResult::Miss,
+            );
+            let failed_dep = tokio::select! {
+                _ = shutdown_rx.wait_for(|&v| v) => None,
+                failed_dep = wait_for_dependencies(&mut deps) => failed_dep,
+            };
+            // Upstream tasks stopped by a shutdown also report Failed, and may
+            // do so before this task is notified of the shutdown itself.
+            if let Some(dep) = failed_dep
+                && !*shutdown_rx.borrow()
+            {
+                writeln!(task, "dependency '{dep}' failed, not starting").ok();
+                mark_failed(&ready_tx, &task);
+                return 1;
             }
         }
-    }
 
-    if *shutdown_rx.borrow() {
-        ready_tx.send(true).ok();
-        task.failed();
-        return;
-    }
+        if *shutdown_rx.borrow() {
+            mark_failed(&ready_tx, &task);
+            return 0;
+        }
 
-    sender.status(
-        name.clone(),
-        "running".into(),
-        tui::event::CacheResult::Miss,
-    );
+        // Held for as long as the child runs so `--max-parallel` is respected.
+        let permit = match &limiter {
+            Some(limiter) => match acquire_permit(limiter, &sender, &name, &mut shutdown_rx).await {
+                Some(permit) => Some(permit),
+                None => {
+                    mark_failed(&ready_tx, &task);
+                    return 0;
+                }
+            },
+            None => None,
+        };
 
-    let child = Command::new("sh")
-        .args(["-c", &command])
-        .current_dir(work_dir)
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn();
+        let status = if attempt == 0 {
+            "running".to_string()
+        } else {
+            format!("running (attempt {})", attempt + 1)
+        };
+        sender.status(name.clone(), status, tui::event::CacheResult::Miss);
 
-    let mut child = match child {
-        Ok(c) => c,
-        Err(e) => {
-            writeln!(task, "failed to spawn command: {e}").ok();
-            task.failed();
-            ready_tx.send(true).ok();
-            return;
+        // Requests that arrived before the child started have nothing to restart.
+        while let Ok(ack) = restart_rx.try_recv() {
+            pending_acks.extend(ack);
         }
-    };
 
-    let pid = child.id().unwrap_or(0);
-    if pid > 0 {
-        pidfile.lock().await.register(pid);
-    }
+        let child = command(&argv)
+            .current_dir(&work_dir)
+            .envs(entry.env.iter().map(|(k, v)| (k, v)))
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn();
 
-    if ready_check.is_none() {
-        ready_tx.send(true).ok();
-    }
+        let mut child = match child {
+            Ok(c) => c,
+            Err(e) => {
+                writeln!(task, "failed to spawn command: {e}").ok();
+                mark_failed(&ready_tx, &task);
+                return 1;
+            }
+        };
 
-    let ready_tx = Arc::new(ready_tx);
-    let stdout = child.stdout.take().unwrap();
-    let stderr = child.stderr.take().unwrap();
+        let pid = child.id().unwrap_or(0);
+        if pid > 0 {
+            pidfile.lock().await.register(pid);
+        }
+        for ack in pending_acks.drain(..) {
+            ack.send(()).ok();
+        }
+
+        if entry.ready_check.is_none() && entry.ready_port.is_none() {
+            mark_ready(&ready_tx);
+        }
+
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
 
-    let stdout_task = {
-        let mut task = sender.task(name.clone());
-        let ready_tx = ready_tx.clone();
-        tokio::spawn(async move {
-            let mut reader = BufReader::new(stdout).lines();
-            while let Ok(Some(line)) = reader.next_line().await {
-                if let Some(ref check) = ready_check {
-                    if line.trim().contains(check.as_str()) {
-                        ready_tx.send(true).ok();
+        let stdout_task = {
+            let mut task = sender.task(name.clone());
+            let ready_tx = ready_tx.clone();
+            let ready_check = entry.ready_check.clone();
+            let log = log.clone();
+            let prefix = prefix.clone();
+            tokio::spawn(async move {
+                let mut reader = BufReader::new(stdout).lines();
+                while let Ok(Some(line)) = reader.next_line().await {
+                    let read_at = Local::now();
+                    if let Some(ref check) = ready_check
+                        && check.matches(&line)
+                    {
+                        mark_ready(&ready_tx);
                     }
+                    emit_line(&line, read_at, prefix.as_ref(), log.as_deref(), &mut task);
                 }
-                writeln!(task, "{line}").ok();
-            }
-        })
-    };
+            })
+        };
+
+        let stderr_task = {
+            let mut task = sender.task(name.clone());
+            let log = log.clone();
+            let prefix = prefix.clone();
+            tokio::spawn(async move {
+                let mut reader = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = reader.next_line().await {
+                    let read_at = Local::now();
+                    emit_line(&line, read_at, prefix.as_ref(), log.as_deref(), &mut task);
+                }
+            })
+        };
 
-    let stderr_task = {
-        let mut task = sender.task(name.clone());
-        tokio::spawn(async move {
-            let mut reader = BufReader::new(stderr).lines();
-            while let Ok(Some(line)) = reader.next_line().await {
-                writeln!(task, "{line}").ok();
+        let shutdown_fut = async {
+            loop {
+                if shutdown_rx.changed().await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+                if *shutdown_rx.borrow() {
+                    break;
+                }
             }
-        })
-    };
+        };
 
-    let shutdown_fut = async {
-        loop {
-            if shutdown_rx.changed().await.is_err() {
-                std::future::pending::<()>().await;
+        // Resolves only if the task is still not ready once `ready_timeout` elapses.
+        let ready_deadline = async {
+            match entry.ready_timeout {
+                Some(limit) if *ready_tx.borrow() == TaskState::Pending => {
+                    let mut rx = ready_tx.subscribe();
+                    let ready = rx.wait_for(|&state| state != TaskState::Pending);
+                    if tokio::time::timeout(limit, ready).await.is_ok() {
+                        std::future::pending::<()>().await;
+                    }
+                    limit
+                }
+                _ => std::future::pending().await,
             }
-            if *shutdown_rx.borrow() {
-                break;
+        };
+
+        // Resolves once the process has been running for `timeout`, if one is set.
+        let run_deadline = async {
+            match entry.timeout {
+                Some(limit) => {
+                    sleep(limit).await;
+                    limit
+                }
+                None => std::future::pending().await,
             }
-        }
-    };
+        };
 
-    tokio::select! {
-        status = child.wait() => {
-            stdout_task.await.ok();
-            stderr_task.await.ok();
-            ready_tx.send(true).ok();
-            if pid > 0 {
-                pidfile.lock().await.unregister(pid);
+        // Never resolves; marks the task ready once its port accepts connections.
+        let port_ready = async {
+            if let Some(port) = entry.ready_port
+                && *ready_tx.borrow() == TaskState::Pending
+            {
+                wait_for_port(port).await;
+                mark_ready(&ready_tx);
             }
-            match status {
-                Ok(s) if s.success() => {
-                    task.succeeded(false);
+            std::future::pending::<()>().await
+        };
+
+        let readers = [stdout_task, stderr_task];
+
+        tokio::select! {
+            _ = port_ready => unreachable!("port polling never completes"),
+            status = child.wait() => {
+                drain_output(readers).await;
+                if pid > 0 {
+                    pidfile.lock().await.unregister(pid);
+                }
+                let exit_code = match status {
+                    Ok(s) if s.success() => 0,
+                    Ok(s) => {
+                        let code = s.code().unwrap_or(-1);
+                        writeln!(task, "process exited with code {code}").ok();
+                        code.max(1)
+                    }
+                    Err(e) => {
+                        writeln!(task, "error waiting for process: {e}").ok();
+                        1
+                    }
+                };
+                let success = exit_code == 0;
+                drop(permit);
+
+                if success {
+                    ready_tx.send(TaskState::Succeeded).ok();
+                }
+
+                if entry.should_restart(success, attempt) && !*shutdown_rx.borrow() {
+                    attempt += 1;
+                    writeln!(task, "restarting in {}ms (attempt {})", entry.backoff_ms, attempt + 1).ok();
+                    sender.status(
+                        name.clone(),
+                        format!("restarting (attempt {})", attempt + 1),
+                        tui::event::CacheResult::Miss,
+                    );
+                    tokio::select! {
+                        _ = sleep(Duration::from_millis(entry.backoff_ms)) => continue,
+                        Some(ack) = restart_rx.recv() => {
+                            pending_acks.extend(ack);
+                            attempt = 0;
+                            continue;
+                        }
+                        _ = shutdown_rx.wait_for(|&v| v) => {
+                            mark_failed(&ready_tx, &task);
+                            return 0;
+                        }
+                    }
                 }
-                Ok(s) => {
-                    let code = s.code().unwrap_or(-1);
-                    writeln!(task, "process exited with code {code}").ok();
-                    task.failed();
+
+                // Requests sent since the process exited were delivered, so
+                // they must be served here rather than dropped.
+                if !*shutdown_rx.borrow() && take_restart_requests(&mut restart_rx, &mut pending_acks) {
+                    writeln!(task, "restarting on request").ok();
+                    ready_tx.send_replace(TaskState::Pending);
+                    attempt = 0;
+                    continue;
                 }
-                Err(e) => {
-                    writeln!(task, "error waiting for process: {e}").ok();
-                    task.failed();
+
+                if success {
+                    task.succeeded(false);
+                } else {
+                    mark_failed(&ready_tx, &task);
                 }
+                return exit_code;
+            }
+            limit = ready_deadline => {
+                writeln!(task, "task did not become ready within {limit:?}, stopping it").ok();
+                stop_process(&mut child, pid, entry.kill_timeout, &pidfile, readers).await;
+                mark_failed(&ready_tx, &task);
+                return 1;
+            }
+            // A timeout is final: the task is not restarted, whatever its policy.
+            limit = run_deadline => {
+                writeln!(task, "timed out after {limit:?}, stopping it").ok();
+                stop_process(&mut child, pid, entry.kill_timeout, &pidfile, readers).await;
+                mark_failed(&ready_tx, &task);
+                return 1;
+            }
+            Some(ack) = restart_rx.recv() => {
+                pending_acks.extend(ack);
+                writeln!(task, "restarting on request").ok();
+                stop_process(&mut child, pid, entry.kill_timeout, &pidfile, readers).await;
+                ready_tx.send_replace(TaskState::Pending);
+                attempt = 0;
             }
+            _ = shutdown_fut => {
+                stop_process(&mut child, pid, entry.kill_timeout, &pidfile, readers).await;
+                mark_failed(&ready_tx, &task);
+                return 0;
+            }
+        }
+    }
+}
+
+/// The command that runs `argv`. `cmd` splits its command line itself instead
+/// of following the quoting `Command::args` applies, so on Windows the command
+/// after `/C` is passed to it as is.
+fn command(argv: &[String]) -> Command {
+    let mut command = Command::new(&argv[0]);
+    #[cfg(windows)]
+    if let [program, flag, line] = argv
+        && flag == "/C"
+        && crate::config::is_cmd(program)
+    {
+        command.arg(flag).raw_arg(line);
+        return command;
+    }
+    command.args(&argv[1..]);
+    command
+}
+
+/// The `[12:03:41 api] ` label put in front of each output line when
+/// `--prefix` or `--timestamps` is enabled.
+#[derive(Clone)]
+pub struct LinePrefix {
+    name: Option<String>,
+    timestamps: bool,
+    style: Style,
+    color_config: ColorConfig,
+}
+
+impl LinePrefix {
+    pub fn new(
+        name: &str,
+        show_name: bool,
+        timestamps: bool,
+        style: Style,
+        color_config: ColorConfig,
+    ) -> Self {
+        Self {
+            name: show_name.then(|| name.to_string()),
+            timestamps,
+            style,
+            color_config,
+        }
+    }
+
+    /// The uncolored label for a line read at `read_at`, including the
+    /// trailing space.
+    fn label(&self, read_at: DateTime<Local>) -> String {
+        let time = self.timestamps.then(|| read_at.format("%H:%M:%S").to_string());
+        let parts: Vec<&str> = time.as_deref().into_iter().chain(self.name.as_deref()).collect();
+        format!("[{}] ", parts.join(" "))
+    }
+}
+
+/// Write one line of output to the task's pane and log file. With a prefix,
+/// the pane gets it in the task's color (when colors are enabled) and the log
+/// file gets it uncolored.
+fn emit_line(
+    line: &str,
+    read_at: DateTime<Local>,
+    prefix: Option<&LinePrefix>,
+    log: Option<&LogFile>,
+    task: &mut TaskSender,
+) {
+    let Some(prefix) = prefix else {
+        if let Some(log) = log {
+            log.write_line(line, task);
         }
-        _ = shutdown_fut => {
-            if pid > 0 {
-                let _ = kill_tree::tokio::kill_tree(pid).await;
-                pidfile.lock().await.unregister(pid);
-            } else {
-                child.kill().await.ok();
+        writeln!(task, "{line}").ok();
+        return;
+    };
+    let label = prefix.label(read_at);
+    if let Some(log) = log {
+        log.write_line(&format!("{label}{line}"), task);
+    }
+    let label = prefix.color_config.apply(prefix.style.apply_to(label));
+    writeln!(task, "{label}{line}").ok();
+}
+
+/// A task's on-disk log, shared by its stdout and stderr readers.
+struct LogFile {
+    path: String,
+    file: std::sync::Mutex<Option<File>>,
+}
+
+impl LogFile {
+    /// Open the log file for appending, reporting failures in the task's pane.
+    /// Logs are truncated once at startup, so restarts add to the same log.
+    fn open(path: &str, task: &mut TaskSender) -> Option<Arc<Self>> {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(Arc::new(Self {
+                path: path.to_string(),
+                file: std::sync::Mutex::new(Some(file)),
+            })),
+            Err(e) => {
+                writeln!(task, "failed to open log file '{path}': {e}").ok();
+                None
             }
-            stdout_task.abort();
-            stderr_task.abort();
-            ready_tx.send(true).ok();
-            task.failed();
         }
     }
+
+    /// Append one line. On the first write error the log is disabled so the
+    /// failure is only reported once.
+    fn write_line(&self, line: &str, task: &mut TaskSender) {
+        let mut file = self.file.lock().expect("log file lock poisoned");
+        if let Some(f) = file.as_mut()
+            && let Err(e) = f.write_all(format!("{line}\n").as_bytes())
+        {
+            writeln!(task, "failed to write log file '{}': {e}", self.path).ok();
+            *file = None;
+        }
+    }
+}
+
+/// Stop a running child, then collect the rest of its output.
+async fn stop_process(
+    child: &mut Child,
+    pid: u32,
+    kill_timeout: Duration,
+    pidfile: &Mutex<PidFile>,
+    readers: [JoinHandle<()>; 2],
+) {
+    stop_child(child, pid, kill_timeout).await;
+    if pid > 0 {
+        pidfile.lock().await.unregister(pid);
+    }
+    drain_output(readers).await;
+}
+
+/// Report the task as failed to its dependents and in its pane.
+fn mark_failed(ready_tx: &watch::Sender<TaskState>, task: &TaskSender) {
+    ready_tx.send(TaskState::Failed).ok();
+    task.failed();
+}
+
+/// Move any queued restart requests into `acks`, returning whether there were
+/// any. With none queued the channel is closed first, so later requests fail
+/// to send and the task is launched again instead.
+fn take_restart_requests(
+    restart_rx: &mut mpsc::UnboundedReceiver<RestartRequest>,
+    acks: &mut Vec<oneshot::Sender<()>>,
+) -> bool {
+    let mut requested = false;
+    for close in [false, true] {
+        if requested {
+            break;
+        }
+        if close {
+            restart_rx.close();
+        }
+        while let Ok(ack) = restart_rx.try_recv() {
+            requested = true;
+            acks.extend(ack);
+        }
+    }
+    requested
+}
+
+/// Wait for the output readers to reach the end of their pipes, abandoning
+/// them after `OUTPUT_WAIT`.
+async fn drain_output(readers: [JoinHandle<()>; 2]) {
+    let deadline = Instant::now() + OUTPUT_WAIT;
+    for mut reader in readers {
+        if tokio::time::timeout_at(deadline, &mut reader).await.is_err() {
+            reader.abort();
+        }
+    }
+}
+
+/// Wait until every dependency is ready, or has exited successfully for
+/// `depends_on_success`. Returns the name of the first one that failed instead.
+async fn wait_for_dependencies(deps: &mut [Dependency]) -> Option<String> {
+    for dep in deps {
+        let until_success = dep.until_success;
+        let failed = dep
+            .state
+            .wait_for(|&state| match state {
+                TaskState::Pending => false,
+                TaskState::Ready => !until_success,
+                TaskState::Succeeded | TaskState::Failed => true,
+            })
+            .await
+            .is_ok_and(|state| *state == TaskState::Failed);
+        if failed {
+            return Some(dep.name.clone());
+        }
+    }
+    None
+}
+
+/// Stop a child and its process tree: SIGTERM first, then SIGKILL for
+/// anything still running once `grace_period` has elapsed. Descendants that
+/// outlive the child get the rest of the grace period too.
+async fn stop_child(child: &mut Child, pid: u32, grace_period: Duration) {
+    if pid == 0 {
+        child.kill().await.ok();
+        return;
+    }
+    let deadline = Instant::now() + grace_period;
+    let tree = PidFile::term_pid_tree(pid).await;
+    let exited = tokio::time::timeout_at(deadline, child.wait()).await.is_ok();
+    while Instant::now() < deadline && PidFile::any_running(&tree) {
+        sleep(Duration::from_millis(50)).await;
+    }
+    PidFile::kill_pids(&tree);
+    if !exited {
+        PidFile::kill_pid_tree_forcefully(pid).await;
+        if tokio::time::timeout(KILL_WAIT, child.wait()).await.is_err() {
+            child.start_kill().ok();
+        }
+    }
+}
+
+/// Wait for a free slot in the concurrency limit, showing the task as queued
+/// while it waits. Returns `None` if shutdown is requested first.
+async fn acquire_permit(
+    limiter: &Arc<Semaphore>,
+    sender: &TuiSender,
+    name: &str,
+    shutdown_rx: &mut watch::Receiver<bool>,
+) -> Option<OwnedSemaphorePermit> {
+    if let Ok(permit) = limiter.clone().try_acquire_owned() {
+        return Some(permit);
+    }
+    sender.status(
+        name.to_string(),
+        "queued".into(),
+        tui::event::CacheResult::Miss,
+    );
+    tokio::select! {
+        permit = limiter.clone().acquire_owned() => permit.ok(),
+        _ = shutdown_rx.wait_for(|&v| v) => None,
+    }
+}
+
+/// Poll a local TCP port until something is listening on it.
+async fn wait_for_port(port: u16) {
+    while TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+        sleep(Duration::from_millis(250)).await;
+    }
+}
+
+/// Signal readiness to dependents, but only on the first transition so a
+/// restarted task doesn't wake them again.
+fn mark_ready(ready_tx: &watch::Sender<TaskState>) {
+    if *ready_tx.borrow() == TaskState::Pending {
+        ready_tx.send(TaskState::Ready).ok();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn stop_child_sends_sigterm_first() {
+        let mut child = Command::new("sh")
+            .args(["-c", "trap 'echo caught; exit 0' TERM; echo started; while true; do sleep 0.1; done"])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pid = child.id().unwrap();
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("started"));
+
+        stop_child(&mut child, pid, Duration::from_secs(5)).await;
+
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("caught"));
+        assert!(child.try_wait().unwrap().unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn stop_child_kills_processes_that_ignore_sigterm() {
+        let mut child = Command::new("sh")
+            .args(["-c", "trap '' TERM; echo started; while true; do sleep 0.1; done"])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pid = child.id().unwrap();
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("started"));
+
+        let grace_period = Duration::from_millis(300);
+        let started = Instant::now();
+        stop_child(&mut child, pid, grace_period).await;
+
+        assert!(started.elapsed() < grace_period + Duration::from_secs(2));
+        assert!(child.try_wait().unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn stop_child_kills_orphaned_grandchildren() {
+        // The outer shell exits on SIGTERM; the inner one ignores it and is reparented.
+        let script = "sh -c 'trap \"\" TERM; while true; do sleep 0.1; done' & echo $!; wait";
+        let mut child = Command::new("sh")
+            .args(["-c", script])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pid = child.id().unwrap();
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        let inner: u32 = lines.next_line().await.unwrap().unwrap().parse().unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        stop_child(&mut child, pid, Duration::from_millis(300)).await;
+
+        sleep(Duration::from_millis(100)).await;
+        assert!(!PidFile::any_running(&[inner]));
+    }
+
+    #[test]
+    fn queued_restart_requests_are_taken_before_closing() {
+        let (restart_tx, mut restart_rx) = mpsc::unbounded_channel();
+        let mut acks = Vec::new();
+        let (ack_tx, _ack_rx) = oneshot::channel();
+        restart_tx.send(Some(ack_tx)).unwrap();
+        restart_tx.send(None).unwrap();
+
+        assert!(take_restart_requests(&mut restart_rx, &mut acks));
+        assert_eq!(acks.len(), 1);
+        assert!(restart_tx.send(None).is_ok(), "channel closed while restarting");
+
+        restart_rx.try_recv().unwrap();
+        assert!(!take_restart_requests(&mut restart_rx, &mut acks));
+        assert!(restart_tx.send(None).is_err());
+    }
+
+    #[tokio::test]
+    async fn wait_for_port_resolves_once_listening() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let listener = tokio::spawn(async move {
+            sleep(Duration::from_millis(300)).await;
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+            listener.accept().await.ok();
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), wait_for_port(port))
+            .await
+            .expect("port never became reachable");
+        listener.abort();
+    }
+
+    #[tokio::test]
+    async fn manual_restart_waits_for_dependencies_again() {
+        let dir = std::env::temp_dir().join(format!("tequio-restart-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let entry = TaskEntry {
+            depends_on: vec!["api".to_string()],
+            kill_timeout: Duration::from_secs(1),
+            ..TaskEntry::for_test("web", "sleep 30")
+        };
+        let (sender, _app) = TuiSender::new();
+        let (api_tx, api_rx) = watch::channel(TaskState::Ready);
+        let deps = vec![Dependency { name: "api".to_string(), state: api_rx, until_success: false }];
+        let ready_tx = Arc::new(watch::channel(TaskState::Pending).0);
+        let mut ready_rx = ready_tx.subscribe();
+        let (restart_tx, restart_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let context = TaskContext {
+            sender,
+            shutdown_rx,
+            pidfile: Arc::new(Mutex::new(PidFile::in_dir(&dir))),
+            limiter: None,
+        };
+        let task = tokio::spawn(run_task(
+            context,
+            entry,
+            dir.to_string_lossy().into_owned(),
+            ready_tx,
+            deps,
+            restart_rx,
+            None,
+        ));
+        ready_rx.wait_for(|&state| state == TaskState::Ready).await.unwrap();
+
+        // The upstream is being restarted as well, so this task must not come
+        // back until it is ready again.
+        api_tx.send_replace(TaskState::Pending);
+        let (ack_tx, mut ack_rx) = oneshot::channel();
+        restart_tx.send(Some(ack_tx)).unwrap();
+        sleep(Duration::from_millis(500)).await;
+        assert!(matches!(ack_rx.try_recv(), Err(oneshot::error::TryRecvError::Empty)));
+        assert_eq!(*ready_rx.borrow(), TaskState::Pending);
+
+        api_tx.send_replace(TaskState::Ready);
+        tokio::time::timeout(Duration::from_secs(5), ack_rx)
+            .await
+            .expect("task never restarted")
+            .unwrap();
+
+        shutdown_tx.send_replace(true);
+        assert_eq!(task.await.unwrap(), 0);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn upstreams_stopped_by_a_shutdown_do_not_fail_dependents() {
+        let dir = std::env::temp_dir().join(format!("tequio-shutdown-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let entry = TaskEntry {
+            depends_on: vec!["api".to_string()],
+            ..TaskEntry::for_test("web", "true")
+        };
+        let (sender, _app) = TuiSender::new();
+        let (api_tx, api_rx) = watch::channel(TaskState::Pending);
+        let deps = vec![Dependency { name: "api".to_string(), state: api_rx, until_success: false }];
+        let (_restart_tx, restart_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let context = TaskContext {
+            sender,
+            shutdown_rx,
+            pidfile: Arc::new(Mutex::new(PidFile::in_dir(&dir))),
+            limiter: None,
+        };
+        let ready_tx = Arc::new(watch::channel(TaskState::Pending).0);
+        let task = tokio::spawn(run_task(
+            context,
+            entry,
+            dir.to_string_lossy().into_owned(),
+            ready_tx,
+            deps,
+            restart_rx,
+            None,
+        ));
+        sleep(Duration::from_millis(100)).await;
+
+        // What the upstream does when it is stopped while still waiting.
+        shutdown_tx.send_replace(true);
+        api_tx.send_replace(TaskState::Failed);
+
+        assert_eq!(task.await.unwrap(), 0);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn background_processes_holding_output_do_not_block_exit() {
+        let dir = std::env::temp_dir().join(format!("tequio-background-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (sender, _app) = TuiSender::new();
+        let (_restart_tx, restart_rx) = mpsc::unbounded_channel();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let context = TaskContext {
+            sender,
+            shutdown_rx,
+            pidfile: Arc::new(Mutex::new(PidFile::in_dir(&dir))),
+            limiter: None,
+        };
+        let run = run_task(
+            context,
+            TaskEntry::for_test("server", "sleep 3 & echo started"),
+            dir.to_string_lossy().into_owned(),
+            Arc::new(watch::channel(TaskState::Pending).0),
+            Vec::new(),
+            restart_rx,
+            None,
+        );
+
+        let exit_code = tokio::time::timeout(Duration::from_secs(2), run)
+            .await
+            .expect("task waited for the background process");
+        assert_eq!(exit_code, 0);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn output_printed_while_stopping_is_kept() {
+        let dir = std::env::temp_dir().join(format!("tequio-stop-output-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log_file = dir.join("web.log");
+        let entry = TaskEntry {
+            log_file: Some(log_file.to_string_lossy().into_owned()),
+            ..TaskEntry::for_test(
+                "web",
+                "trap 'echo stopping; exit 0' TERM; echo started; while true; do sleep 0.1; done",
+            )
+        };
+        let (sender, _app) = TuiSender::new();
+        let (_restart_tx, restart_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let context = TaskContext {
+            sender,
+            shutdown_rx,
+            pidfile: Arc::new(Mutex::new(PidFile::in_dir(&dir))),
+            limiter: None,
+        };
+        let task = tokio::spawn(run_task(
+            context,
+            entry,
+            dir.to_string_lossy().into_owned(),
+            Arc::new(watch::channel(TaskState::Pending).0),
+            Vec::new(),
+            restart_rx,
+            None,
+        ));
+        while !std::fs::read_to_string(&log_file).unwrap_or_default().contains("started") {
+            sleep(Duration::from_millis(50)).await;
+        }
+
+        shutdown_tx.send_replace(true);
+        assert_eq!(task.await.unwrap(), 0);
+        let log = std::fs::read_to_string(&log_file).unwrap();
+        assert!(log.lines().any(|line| line == "stopping"), "log was {log:?}");
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
@@ -0,0 +1,156 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use glob::{MatchOptions, Pattern};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+
+/// How long the files must stay quiet before a burst of changes restarts the task.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// `*` stays within one path component; only `**` crosses directories.
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Watch the files matching a task's `watch` patterns, relative to its working
+/// directory, and send the task's name on `restart_tx` once a burst of changes
+/// has settled. The restart stays in flight until the sender it comes with is
+/// notified or dropped, and changes that land meanwhile are dropped. Watching
+/// stops when the returned watcher is dropped.
+pub fn watch_task(
+    name: String,
+    work_dir: &Path,
+    patterns: &[String],
+    restart_tx: mpsc::UnboundedSender<(String, oneshot::Sender<()>)>,
+) -> notify::Result<RecommendedWatcher> {
+    // Event paths are absolute and canonical, so match against the same.
+    let work_dir = work_dir.canonicalize().unwrap_or_else(|_| work_dir.to_path_buf());
+    let base = Pattern::escape(&work_dir.to_string_lossy());
+    let globs: Vec<Pattern> = patterns
+        .iter()
+        .filter_map(|pattern| Pattern::new(&format!("{base}/{pattern}")).ok())
+        .collect();
+
+    let (event_tx, event_rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        if matches!(event.kind, EventKind::Access(_)) {
+            return;
+        }
+        let matched = event
+            .paths
+            .iter()
+            .any(|path| globs.iter().any(|glob| glob.matches_path_with(path, MATCH_OPTIONS)));
+        if matched {
+            event_tx.send(()).ok();
+        }
+    })?;
+
+    let mut roots: Vec<(PathBuf, RecursiveMode)> = Vec::new();
+    for root in patterns.iter().map(|pattern| watch_root(&work_dir, pattern)) {
+        if !roots.contains(&root) {
+            watcher.watch(&root.0, root.1)?;
+            roots.push(root);
+        }
+    }
+
+    tokio::spawn(debounce(name, event_rx, restart_tx));
+    Ok(watcher)
+}
+
+/// The directory to watch for a pattern: the components before its first
+/// wildcard, recursively if the wildcard is not in the last component. A
+/// pattern naming a single file watches the file's directory, so editors that
+/// save by replacing the file are still seen.
+fn watch_root(work_dir: &Path, pattern: &str) -> (PathBuf, RecursiveMode) {
+    let mut root = work_dir.to_path_buf();
+    let mut components = Path::new(pattern).components().peekable();
+    while let Some(component) = components.next() {
+        let is_last = components.peek().is_none();
+        if component.as_os_str().to_string_lossy().contains(['*', '?', '[']) {
+            let mode = if is_last {
+                RecursiveMode::NonRecursive
+            } else {
+                RecursiveMode::Recursive
+            };
+            return (root, mode);
+        }
+        if is_last {
+            break;
+        }
+        root.push(component);
+    }
+    (root, RecursiveMode::NonRecursive)
+}
+
+async fn debounce(
+    name: String,
+    mut events: mpsc::UnboundedReceiver<()>,
+    restart_tx: mpsc::UnboundedSender<(String, oneshot::Sender<()>)>,
+) {
+    while events.recv().await.is_some() {
+        // Coalesce a burst of saves into a single restart.
+        while let Ok(Some(())) = timeout(DEBOUNCE, events.recv()).await {}
+
+        let (ack_tx, ack_rx) = oneshot::channel();
+        if restart_tx.send((name.clone(), ack_tx)).is_err() {
+            return;
+        }
+        // The restart is in flight until the replacement process has been
+        // spawned, or the task gave up without spawning one.
+        ack_rx.await.ok();
+        while events.try_recv().is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_burst_of_changes_restarts_once() {
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let (restart_tx, mut restart_rx) = mpsc::unbounded_channel();
+        tokio::spawn(debounce("web".to_string(), event_rx, restart_tx));
+
+        for _ in 0..5 {
+            event_tx.send(()).unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let (name, ack) = restart_rx.recv().await.unwrap();
+        assert_eq!(name, "web");
+
+        // Changes made while the restart is in flight are dropped.
+        for _ in 0..5 {
+            event_tx.send(()).unwrap();
+        }
+        tokio::time::sleep(DEBOUNCE * 2).await;
+        assert!(restart_rx.try_recv().is_err());
+        ack.send(()).unwrap();
+        tokio::time::sleep(DEBOUNCE * 2).await;
+        assert!(restart_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn watch_root_stops_at_the_first_wildcard() {
+        let dir = Path::new("/app");
+        assert_eq!(
+            watch_root(dir, "src/**/*.rs"),
+            (PathBuf::from("/app/src"), RecursiveMode::Recursive)
+        );
+        assert_eq!(
+            watch_root(dir, "src/*.rs"),
+            (PathBuf::from("/app/src"), RecursiveMode::NonRecursive)
+        );
+        assert_eq!(
+            watch_root(dir, "config/app.toml"),
+            (PathBuf::from("/app/config"), RecursiveMode::NonRecursive)
+        );
+    }
+}